@@ -0,0 +1,40 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Errors used across the subsystem crate.
+
+/// Errors that can arise from the [`crate::jaeger`] module.
+#[derive(Debug, thiserror::Error)]
+pub enum JaegerError {
+	/// [`crate::jaeger::Jaeger::launch`] was called on an already-launched instance.
+	#[error("Jaeger already launched")]
+	AlreadyLaunched,
+	/// [`crate::jaeger::Jaeger::launch`] was called without a configuration having been set.
+	#[error("Missing jaeger configuration")]
+	MissingConfiguration,
+	/// No local UDP port could be allocated for the agent transport.
+	#[error("Could not allocate a local port to send jaeger spans from: {0}")]
+	PortAllocationError(std::io::Error),
+	/// Sending a span batch over UDP failed.
+	#[error("Failed to send jaeger span: {0}")]
+	SendError(std::io::Error),
+	/// [`crate::jaeger::Jaeger::shutdown`] was called while jaeger was not launched.
+	#[error("Jaeger is not launched")]
+	NotLaunched,
+	/// The background task could not be reached to signal a shutdown.
+	#[error("Failed to signal the jaeger background task to shut down")]
+	ShutdownFailed,
+}
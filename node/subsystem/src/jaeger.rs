@@ -40,11 +40,17 @@
 //!  jaegertracing/all-in-one:1.21
 //! ```
 //!
+//! Known limitation: spans created on different nodes are not linked as parent/child in the
+//! Jaeger UI. [`SpanContext`] lets a receiving node correlate its spans with one from another
+//! node via a best-effort tag, but this is not a substitute for a real cross-node edge; see the
+//! caveat on [`SpanContext`] for what would be needed to close that gap.
+//!
 
-use polkadot_primitives::v1::{Hash, PoV, CandidateHash};
+use polkadot_primitives::v1::{Hash, PoV, CandidateHash, Id as ParaId};
 use parking_lot::Mutex;
 use std::sync::Arc;
 use std::result;
+use std::time::{Duration, Instant};
 pub use crate::errors::JaegerError;
 
 
@@ -56,18 +62,30 @@ lazy_static::lazy_static! {
 #[derive(Clone)]
 pub struct JaegerConfig {
 	node_name: String,
-	agent_addr: std::net::SocketAddr,
+	transport: Transport,
+	sampler: Sampler,
 }
 
 impl std::default::Default for JaegerConfig {
 	fn default() -> Self {
 		Self {
 			node_name: "unknown_".to_owned(),
-			agent_addr: "127.0.0.1:6831".parse().unwrap(),
+			transport: Transport::Agent("127.0.0.1:6831".parse().unwrap()),
+			sampler: Sampler::Const(true),
 		}
 	}
 }
 
+/// The wire transport used to ship spans to jaeger.
+#[derive(Clone, Debug)]
+pub enum Transport {
+	/// Send spans as compact-thrift UDP packets to the jaeger agent.
+	Agent(std::net::SocketAddr),
+	/// POST spans as compact-thrift batches to a jaeger collector, e.g. behind a
+	/// proxy/firewall that only allows outbound HTTP.
+	Collector(url::Url),
+}
+
 impl JaegerConfig {
 	/// Use the builder pattern to construct a configuration.
 	pub fn builder() -> JaegerConfigBuilder {
@@ -89,9 +107,27 @@ impl JaegerConfigBuilder {
 		self
 	}
 
-	/// Set the agent address to send the collected spans to.
+	/// Send collected spans via UDP to the jaeger agent at `addr`.
+	///
+	/// This is the default transport.
 	pub fn agent<U>(mut self, addr: U) -> Self where U: Into<std::net::SocketAddr> {
-		self.inner.agent_addr = addr.into();
+		self.inner.transport = Transport::Agent(addr.into());
+		self
+	}
+
+	/// Send collected spans via HTTP to the jaeger collector at `url`, instead of the
+	/// UDP agent. Useful when nodes can only reach the collector through a proxy.
+	pub fn collector(mut self, url: url::Url) -> Self {
+		self.inner.transport = Transport::Collector(url);
+		self
+	}
+
+	/// Set the sampling policy applied to every span created via [`hash_span`].
+	///
+	/// Defaults to [`Sampler::Const(true)`], i.e. every span is recorded, which
+	/// matches the previous, unconditional behaviour.
+	pub fn sampler(mut self, sampler: Sampler) -> Self {
+		self.inner.sampler = sampler;
 		self
 	}
 
@@ -101,12 +137,164 @@ impl JaegerConfigBuilder {
 	}
 }
 
+/// Decides whether a given span should be recorded.
+#[derive(Clone)]
+pub enum Sampler {
+	/// Always (`true`) or never (`false`) sample.
+	Const(bool),
+	/// Sample a fraction `p` (in `[0, 1]`) of all traces.
+	///
+	/// The decision is derived from the trace id rather than a random draw, so that
+	/// the same candidate/block is sampled consistently across every node in the
+	/// network: a trace id is sampled iff the lower 64 bits of the (128 bit) trace id
+	/// are smaller than `p * u64::MAX`.
+	Probabilistic(f64),
+	/// Limit the number of sampled spans to roughly `spans_per_second`, using a
+	/// token bucket with the given burst capacity.
+	RateLimiting {
+		/// Steady-state number of spans sampled per second.
+		spans_per_second: f64,
+		/// Maximum number of spans that may be sampled in a single burst.
+		burst: f64,
+	},
+}
+
+impl Sampler {
+	/// Probabilistic threshold equivalent to this sampler's probability, i.e.
+	/// a trace is sampled iff `trace_id as u64 < threshold`.
+	///
+	/// Only meaningful for `p` strictly between `0.0` and `1.0`; [`Jaeger::is_sampled`]
+	/// handles the `p <= 0.0`/`p >= 1.0` edges separately, since `u64::MAX as f64` rounds
+	/// up to `2^64` and a saturating cast back to `u64` would otherwise make the single
+	/// trace id equal to `u64::MAX` not sampled even at `p == 1.0`.
+	fn probabilistic_threshold(p: f64) -> u64 {
+		(p.max(0.0).min(1.0) * (u64::MAX as f64)) as u64
+	}
+}
+
+/// Maximum number of batches buffered between `traces_out` and the collector sender
+/// task, bounding memory use if the collector is slow or unreachable.
+const MAX_BUFFERED_COLLECTOR_BATCHES: usize = 64;
+/// Maximum number of retry attempts per batch sent to the collector before giving up
+/// on it.
+const MAX_COLLECTOR_RETRIES: u32 = 5;
+
+/// Exponential backoff delay before the `attempt`'th retry of a failed collector POST.
+fn collector_retry_backoff(attempt: u32) -> Duration {
+	Duration::from_millis(100 * 2u64.pow(attempt))
+}
+
+/// Whether a batch that just failed to send (its `attempt`'th try) should be retried,
+/// as opposed to giving up on it.
+fn should_retry_collector_send(attempt: u32) -> bool {
+	attempt < MAX_COLLECTOR_RETRIES
+}
+
+/// A token bucket used to rate-limit the number of spans sampled per second.
+struct TokenBucket {
+	capacity: f64,
+	tokens: f64,
+	refill_per_sec: f64,
+	last_refill: Instant,
+}
+
+impl TokenBucket {
+	fn new(spans_per_second: f64, burst: f64) -> Self {
+		Self {
+			capacity: burst,
+			tokens: burst,
+			refill_per_sec: spans_per_second,
+			last_refill: Instant::now(),
+		}
+	}
+
+	/// Attempt to take a single token, refilling based on elapsed time first.
+	fn try_acquire(&mut self) -> bool {
+		let now = Instant::now();
+		let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+		self.last_refill = now;
+
+		self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+
+		if self.tokens >= 1.0 {
+			self.tokens -= 1.0;
+			true
+		} else {
+			false
+		}
+	}
+}
+
+/// A propagatable reference to a span, suitable for attaching to a network message so
+/// that the receiving node can correlate its own spans with the one that caused them.
+///
+/// Encodes the same information as the `uber-trace-id` header used by the jaeger/zipkin
+/// propagation format: `{trace_id}:{span_id}:{parent_span_id}:{flags}`, all hex except
+/// for `flags`, whose bit 0 indicates whether the trace is sampled.
+///
+/// # Known limitation: not a real parent/child edge
+///
+/// This does *not* give receiving nodes a true parent/child span edge in the Jaeger UI.
+/// [`mick_jaeger`] only exposes `TracesIn::span(trace_id, name)` and `Span::child(name)`;
+/// neither accepts an externally supplied parent span id, so there is no way for this
+/// crate alone to make a span created on one node a child of a span created on another.
+/// `span_id`/`parent_span_id` here are therefore this crate's own bookkeeping, not
+/// identifiers known to the thrift encoder, and spans from different nodes still render
+/// as separate, flat entries sharing a trace id in the Jaeger UI — the same visual result
+/// as before `SpanContext` existed. [`Jaeger::span`] attaches the encoded context as a
+/// best-effort `parent-span-context` *tag* so operators can still correlate nodes
+/// manually (searchable, but not a rendered tree edge).
+///
+/// Closing the gap for real needs a `mick_jaeger` API that accepts an explicit parent
+/// span id at span-creation time; that is out of scope for this crate and has not been
+/// filed upstream yet. Treat cross-node causality as accepted, degraded scope until then.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpanContext {
+	trace_id: u128,
+	span_id: u64,
+	parent_span_id: u64,
+	sampled: bool,
+}
+
+impl SpanContext {
+	/// Encode as the `uber-trace-id` wire format.
+	pub fn to_uber_trace_id(&self) -> String {
+		format!(
+			"{:x}:{:x}:{:x}:{}",
+			self.trace_id,
+			self.span_id,
+			self.parent_span_id,
+			self.sampled as u8,
+		)
+	}
+
+	/// Decode from the `uber-trace-id` wire format. Returns `None` if `s` is malformed.
+	pub fn from_uber_trace_id(s: &str) -> Option<Self> {
+		let mut parts = s.split(':');
+		let trace_id = u128::from_str_radix(parts.next()?, 16).ok()?;
+		let span_id = u64::from_str_radix(parts.next()?, 16).ok()?;
+		let parent_span_id = u64::from_str_radix(parts.next()?, 16).ok()?;
+		let flags: u8 = parts.next()?.parse().ok()?;
+
+		if parts.next().is_some() {
+			return None;
+		}
+
+		Some(Self {
+			trace_id,
+			span_id,
+			parent_span_id,
+			sampled: flags & 0x1 == 1,
+		})
+	}
+}
+
 /// A wrapper type for a span.
 ///
 /// Handles running with and without jaeger.
 pub enum JaegerSpan {
 	/// Running with jaeger being enabled.
-	Enabled(mick_jaeger::Span),
+	Enabled(mick_jaeger::Span, SpanContext),
 	/// Running with jaeger disabled.
 	Disabled,
 }
@@ -115,32 +303,89 @@ impl JaegerSpan {
 	/// Derive a child span from `self`.
 	pub fn child(&self, name: impl Into<String>) -> Self {
 		match self {
-			Self::Enabled(inner) => Self::Enabled(inner.child(name)),
+			Self::Enabled(inner, ctx) => {
+				let child_ctx = SpanContext {
+					trace_id: ctx.trace_id,
+					span_id: rand::random(),
+					parent_span_id: ctx.span_id,
+					sampled: ctx.sampled,
+				};
+				Self::Enabled(inner.child(name), child_ctx)
+			}
 			Self::Disabled => Self::Disabled,
 		}
 	}
 	/// Add an additional tag to the span.
 	pub fn add_string_tag(&mut self, tag: &str, value: &str) {
 		match self {
-			Self::Enabled(ref mut inner) => inner.add_string_tag(tag, value),
+			Self::Enabled(ref mut inner, _) => inner.add_string_tag(tag, value),
 			Self::Disabled => {},
 		}
 	}
-}
 
-impl From<Option<mick_jaeger::Span>> for JaegerSpan {
-	fn from(src: Option<mick_jaeger::Span>) -> Self {
-		if let Some(span) = src {
-			Self::Enabled(span)
-		} else {
-			Self::Disabled
+	/// Obtain a propagatable [`SpanContext`] for this span, so a receiving node can
+	/// correlate its own spans with it (see the caveat on [`SpanContext`]). Returns
+	/// `None` if jaeger is disabled.
+	pub fn context(&self) -> Option<SpanContext> {
+		match self {
+			Self::Enabled(_, ctx) => Some(ctx.clone()),
+			Self::Disabled => None,
 		}
 	}
+
+	/// Attach the candidate hash as a tag.
+	pub fn with_candidate(mut self, candidate_hash: &CandidateHash) -> Self {
+		self.add_string_tag(tag::CANDIDATE_HASH, &format!("{:?}", candidate_hash.0));
+		self
+	}
+
+	/// Attach the PoV hash as a tag.
+	pub fn with_pov_hash(mut self, pov_hash: &Hash) -> Self {
+		self.add_string_tag(tag::POV_HASH, &format!("{:?}", pov_hash));
+		self
+	}
+
+	/// Attach the validator index as a tag.
+	pub fn with_validator_index(mut self, validator_index: u32) -> Self {
+		self.add_string_tag(tag::VALIDATOR_INDEX, &validator_index.to_string());
+		self
+	}
+
+	/// Attach the para id as a tag.
+	pub fn with_para_id(mut self, para_id: ParaId) -> Self {
+		self.add_string_tag(tag::PARA_ID, &format!("{:?}", para_id));
+		self
+	}
+
+	/// Attach the relay parent hash as a tag.
+	pub fn with_relay_parent(mut self, relay_parent: &Hash) -> Self {
+		self.add_string_tag(tag::RELAY_PARENT, &format!("{:?}", relay_parent));
+		self
+	}
+
+	/// Derive a child span from `self` and attach the candidate hash to it, in one step.
+	pub fn child_with_candidate(&self, name: impl Into<String>, candidate_hash: &CandidateHash) -> Self {
+		self.child(name).with_candidate(candidate_hash)
+	}
+}
+
+/// Canonical tag keys, so that every node emits identically-named tags and traces can be
+/// filtered reliably across the whole network from the Jaeger UI.
+mod tag {
+	pub const CANDIDATE_HASH: &str = "candidate-hash";
+	pub const POV_HASH: &str = "pov-hash";
+	pub const VALIDATOR_INDEX: &str = "validator-index";
+	pub const PARA_ID: &str = "para-id";
+	pub const RELAY_PARENT: &str = "relay-parent";
 }
 
-impl From<mick_jaeger::Span> for JaegerSpan {
-	fn from(src: mick_jaeger::Span) -> Self {
-		Self::Enabled(src)
+impl From<Option<(mick_jaeger::Span, SpanContext)>> for JaegerSpan {
+	fn from(src: Option<(mick_jaeger::Span, SpanContext)>) -> Self {
+		if let Some((span, ctx)) = src {
+			Self::Enabled(span, ctx)
+		} else {
+			Self::Disabled
+		}
 	}
 }
 
@@ -160,7 +405,19 @@ pub fn pov_span(pov: &PoV, span_name: impl Into<String>) -> JaegerSpan {
 /// same hash (even from multiple different nodes) will be visible in the same view on Jaeger.
 #[inline(always)]
 pub fn hash_span(hash: &Hash, span_name: impl Into<String>) -> JaegerSpan {
-	INSTANCE.lock().span(hash, span_name).into()
+	INSTANCE.lock().span(hash, span_name, None).into()
+}
+
+/// Like [`hash_span`], but tags the new span with the [`SpanContext`] of a span on another
+/// node, so the two can be correlated manually in the Jaeger UI. This is *not* a true
+/// cross-node parent/child edge — see the caveat on [`SpanContext`].
+#[inline(always)]
+pub fn hash_span_with_parent(
+	hash: &Hash,
+	span_name: impl Into<String>,
+	parent: &SpanContext,
+) -> JaegerSpan {
+	INSTANCE.lock().span(hash, span_name, Some(parent)).into()
 }
 
 /// Stateful convenience wrapper around [`mick_jaeger`].
@@ -169,6 +426,15 @@ pub enum Jaeger {
 	Launched {
 		/// [`mick_jaeger`] provided API to record spans to.
 		traces_in: Arc<mick_jaeger::TracesIn>,
+		/// Sampling policy applied before recording a span.
+		sampler: Sampler,
+		/// Token bucket backing [`Sampler::RateLimiting`], if configured.
+		rate_limiter: Option<TokenBucket>,
+		/// Configuration this instance was launched with, kept around so
+		/// [`Jaeger::shutdown`] can hand it back via [`Jaeger::Prep`].
+		cfg: JaegerConfig,
+		/// Signals the background task to drain and flush, acknowledging once done.
+		shutdown: async_std::channel::Sender<futures::channel::oneshot::Sender<()>>,
 	},
 	/// Preparation state with the necessary config to launch the collector.
 	Prep(JaegerConfig),
@@ -192,72 +458,379 @@ impl Jaeger {
 			Self::None => Err(JaegerError::MissingConfiguration),
 		}?;
 
-		let jaeger_agent = cfg.agent_addr;
+		let stored_cfg = cfg.clone();
+		let transport = cfg.transport.clone();
 
-		log::info!("🐹 Collecting jaeger spans for {:?}", &jaeger_agent);
+		log::info!("🐹 Collecting jaeger spans via {:?}", &transport);
 
 		let (traces_in, mut traces_out) = mick_jaeger::init(mick_jaeger::Config {
 			service_name: format!("{}-{}", cfg.node_name, cfg.node_name),
 		});
 
+		let rate_limiter = match cfg.sampler.clone() {
+			Sampler::RateLimiting { spans_per_second, burst } => Some(TokenBucket::new(spans_per_second, burst)),
+			_ => None,
+		};
 
-		// Spawn a background task that pulls span information and sends them on the network.
-		let _handle = async_std::task::spawn::<_, result::Result<(), JaegerError>>(async move {
-			let mut port = 49000_u16;
-			let mut udp_socket;
+		let (shutdown_tx, shutdown_rx) = async_std::channel::bounded::<futures::channel::oneshot::Sender<()>>(1);
 
-			loop {
-				udp_socket = async_std::net::UdpSocket::bind(format!("127.0.0.1:{}", port)).await;
-				if udp_socket.is_ok() {
-					break;
-				}
-				port += 1;
-				if port == std::primitive::u16::MAX {
-					break;
+		// Spawn a background task that pulls span information and sends them on the network,
+		// until asked to shut down via `shutdown_rx`.
+		let _handle = async_std::task::spawn::<_, result::Result<(), JaegerError>>(async move {
+			use futures::FutureExt as _;
+
+			match transport {
+				Transport::Agent(jaeger_agent) => {
+					let mut port = 49000_u16;
+					let mut udp_socket;
+
+					loop {
+						udp_socket = async_std::net::UdpSocket::bind(format!("127.0.0.1:{}", port)).await;
+						if udp_socket.is_ok() {
+							break;
+						}
+						port += 1;
+						if port == std::primitive::u16::MAX {
+							break;
+						}
+					}
+					let udp_socket = udp_socket.map_err(|e| JaegerError::PortAllocationError(e))?;
+
+					loop {
+						futures::select! {
+							buf = traces_out.next().fuse() => {
+								// UDP sending errors happen only either if the API is misused (in which
+								// case panicking is desirable) or in case of missing privilege.
+								if let Err(e) = udp_socket.send_to(&buf, jaeger_agent).await
+									.map_err(|e| JaegerError::SendError(e))
+								{
+									log::trace!("Failed to send jaeger span: {:?}", e);
+								}
+							}
+							ack = shutdown_rx.recv().fuse() => {
+								// Drain and flush whatever is still buffered before exiting.
+								while let Ok(buf) = async_std::future::timeout(
+									Duration::from_millis(50),
+									traces_out.next(),
+								).await {
+									let _ = udp_socket.send_to(&buf, jaeger_agent).await;
+								}
+								if let Ok(ack) = ack {
+									let _ = ack.send(());
+								}
+								break;
+							}
+						}
+					}
 				}
-			}
-			let udp_socket = udp_socket.map_err(|e| JaegerError::PortAllocationError(e))?;
-
-			loop {
-				let buf = traces_out.next().await;
-				// UDP sending errors happen only either if the API is misused (in which case
-				// panicking is desirable) or in case of missing privilege.
-				if let Err(e) = udp_socket.send_to(&buf, jaeger_agent).await
-					.map_err(|e| JaegerError::SendError(e))
-				{
-					log::trace!("Failed to send jaeger span: {:?}", e);
+				Transport::Collector(collector_url) => {
+					let client = surf::Client::new();
+					let (batch_tx, batch_rx) =
+						async_std::channel::bounded::<Vec<u8>>(MAX_BUFFERED_COLLECTOR_BATCHES);
+
+					// Posting to the collector (with its own retry/backoff) happens on a
+					// dedicated task, fed through a bounded channel, so a slow collector
+					// stalls at most `MAX_BUFFERED_COLLECTOR_BATCHES` batches deep instead
+					// of blocking this loop from pulling new spans off `traces_out`.
+					let sender = async_std::task::spawn(async move {
+						while let Ok(batch) = batch_rx.recv().await {
+							let mut attempt = 0;
+							loop {
+								let res = client.post(collector_url.clone())
+									.header("Content-Type", "application/vnd.apache.thrift.binary")
+									.body(batch.clone())
+									.await;
+
+								let success = matches!(&res, Ok(response) if response.status().is_success());
+								if success {
+									break;
+								}
+								if !should_retry_collector_send(attempt) {
+									log::trace!(
+										"Failed to send jaeger span batch to collector after {} attempts",
+										attempt + 1,
+									);
+									break;
+								}
+								attempt += 1;
+								async_std::task::sleep(collector_retry_backoff(attempt)).await;
+							}
+						}
+					});
+
+					loop {
+						futures::select! {
+							buf = traces_out.next().fuse() => {
+								// Fire-and-forget: if the sender is falling behind, drop the
+								// batch rather than block span consumption on it.
+								if batch_tx.try_send(buf).is_err() {
+									log::trace!("🐹 jaeger collector is falling behind, dropping a span batch");
+								}
+							}
+							ack = shutdown_rx.recv().fuse() => {
+								while let Ok(buf) = async_std::future::timeout(
+									Duration::from_millis(50),
+									traces_out.next(),
+								).await {
+									let _ = batch_tx.try_send(buf);
+								}
+								batch_tx.close();
+								sender.await;
+								if let Ok(ack) = ack {
+									let _ = ack.send(());
+								}
+								break;
+							}
+						}
+					}
 				}
 			}
+			Ok(())
 		});
 
 
 		*INSTANCE.lock() =Self::Launched {
 			traces_in,
+			sampler: cfg.sampler,
+			rate_limiter,
+			cfg: stored_cfg,
+			shutdown: shutdown_tx,
+		};
+		Ok(())
+	}
+
+	/// Ask the background task to drain and flush any buffered spans, then stop.
+	///
+	/// After this completes, [`Jaeger::launch`] may be called again (the instance is left
+	/// in [`Jaeger::Prep`] with its original configuration). Returns
+	/// [`JaegerError::NotLaunched`] if jaeger was not running.
+	pub async fn shutdown() -> result::Result<(), JaegerError> {
+		let (cfg, shutdown) = {
+			let mut instance = INSTANCE.lock();
+			match std::mem::replace(&mut *instance, Self::None) {
+				Self::Launched { cfg, shutdown, .. } => (cfg, shutdown),
+				other => {
+					*instance = other;
+					return Err(JaegerError::NotLaunched);
+				}
+			}
 		};
+
+		let (ack_tx, ack_rx) = futures::channel::oneshot::channel();
+		let sent = shutdown.send(ack_tx).await;
+
+		// Hand the configuration back either way, so a failed shutdown doesn't
+		// permanently strand `INSTANCE` at `None`.
+		*INSTANCE.lock() = Self::Prep(cfg);
+
+		sent.map_err(|_| JaegerError::ShutdownFailed)?;
+		let _ = ack_rx.await;
+
 		Ok(())
 	}
 
-	#[inline(always)]
-	fn traces_in(&self) -> Option<&Arc<mick_jaeger::TracesIn>> {
+	/// Decide, according to the configured [`Sampler`], whether a trace with the given
+	/// id should be recorded.
+	fn is_sampled(sampler: &Sampler, rate_limiter: &mut Option<TokenBucket>, trace_id: u128) -> bool {
+		match sampler {
+			Sampler::Const(enabled) => *enabled,
+			Sampler::Probabilistic(p) if *p >= 1.0 => true,
+			Sampler::Probabilistic(p) if *p <= 0.0 => false,
+			Sampler::Probabilistic(p) => {
+				let threshold = Sampler::probabilistic_threshold(*p);
+				(trace_id as u64) < threshold
+			}
+			Sampler::RateLimiting { .. } => {
+				rate_limiter.as_mut().map(|bucket| bucket.try_acquire()).unwrap_or(true)
+			}
+		}
+	}
+
+	fn span(
+		&mut self,
+		hash: &Hash,
+		span_name: impl Into<String>,
+		parent: Option<&SpanContext>,
+	) -> Option<(mick_jaeger::Span, SpanContext)> {
 		match self {
-			Self::Launched {
-				traces_in,
-				..
-			} => Some(&traces_in),
+			Self::Launched { traces_in, sampler, rate_limiter, .. } => {
+				let trace_id = {
+					let mut buf = [0u8; 16];
+					buf.copy_from_slice(&hash.as_ref()[0..16]);
+					std::num::NonZeroU128::new(u128::from_be_bytes(buf))
+				}.expect("16 bytes make a u128; qed");
+
+				if !Self::is_sampled(sampler, rate_limiter, trace_id.get()) {
+					return None;
+				}
+
+				let mut span = traces_in.span(trace_id, span_name);
+				if let Some(parent) = parent {
+					// Best-effort correlation only: mick_jaeger has no API to create a span
+					// under an externally supplied parent id, so this does not produce a
+					// real parent/child edge (see the caveat on `SpanContext`).
+					span.add_string_tag("parent-span-context", &parent.to_uber_trace_id());
+				}
+
+				let ctx = SpanContext {
+					trace_id: trace_id.get(),
+					span_id: rand::random(),
+					parent_span_id: parent.map(|p| p.span_id).unwrap_or(0),
+					sampled: true,
+				};
+
+				Some((span, ctx))
+			}
 			_ => None,
 		}
 	}
+}
 
-	fn span(&self, hash: &Hash, span_name: impl Into<String>) -> Option<mick_jaeger::Span> {
-		if let Some(traces_in) = self.traces_in() {
-			let trace_id = {
-				let mut buf = [0u8; 16];
-				buf.copy_from_slice(&hash.as_ref()[0..16]);
-				std::num::NonZeroU128::new(u128::from_be_bytes(buf))
-			}.expect("16 bytes make a u128; qed");
-			Some(traces_in.span(trace_id, span_name))
-		} else {
-			None
-		}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn probabilistic_threshold_scales_with_p() {
+		assert_eq!(Sampler::probabilistic_threshold(0.0), 0);
+		// `u64::MAX as f64` is exactly `2^64`, so half of it is `2^63`.
+		assert_eq!(Sampler::probabilistic_threshold(0.5), 1u64 << 63);
+	}
+
+	#[test]
+	fn is_sampled_const() {
+		let mut none = None;
+		assert!(Jaeger::is_sampled(&Sampler::Const(true), &mut none, 0));
+		assert!(!Jaeger::is_sampled(&Sampler::Const(false), &mut none, u128::from(u64::MAX)));
+	}
+
+	#[test]
+	fn is_sampled_probabilistic_full_probability_samples_every_trace_id() {
+		let mut none = None;
+		// Regression test: `u64::MAX as f64` rounds up to `2^64`, so without the
+		// `p >= 1.0` special case the highest trace id would be wrongly excluded.
+		assert!(Jaeger::is_sampled(&Sampler::Probabilistic(1.0), &mut none, u128::from(u64::MAX)));
+		assert!(Jaeger::is_sampled(&Sampler::Probabilistic(1.0), &mut none, 0));
+	}
+
+	#[test]
+	fn is_sampled_probabilistic_zero_probability_samples_nothing() {
+		let mut none = None;
+		assert!(!Jaeger::is_sampled(&Sampler::Probabilistic(0.0), &mut none, 0));
+		assert!(!Jaeger::is_sampled(&Sampler::Probabilistic(0.0), &mut none, u128::from(u64::MAX)));
+	}
+
+	#[test]
+	fn is_sampled_probabilistic_respects_threshold() {
+		let sampler = Sampler::Probabilistic(0.5);
+		let threshold = Sampler::probabilistic_threshold(0.5);
+		let mut none = None;
+
+		assert!(Jaeger::is_sampled(&sampler, &mut none, u128::from(threshold - 1)));
+		assert!(!Jaeger::is_sampled(&sampler, &mut none, u128::from(threshold)));
+	}
+
+	#[test]
+	fn token_bucket_respects_burst_capacity() {
+		let mut bucket = TokenBucket::new(0.0, 2.0);
+		assert!(bucket.try_acquire());
+		assert!(bucket.try_acquire());
+		// Burst of 2 exhausted, and no time has passed to refill (rate is 0 anyway).
+		assert!(!bucket.try_acquire());
+	}
+
+	#[test]
+	fn token_bucket_refills_over_time() {
+		let mut bucket = TokenBucket::new(1_000.0, 1.0);
+		assert!(bucket.try_acquire());
+		assert!(!bucket.try_acquire());
+
+		std::thread::sleep(Duration::from_millis(10));
+		assert!(bucket.try_acquire());
+	}
+
+	#[test]
+	fn span_context_round_trips_through_uber_trace_id() {
+		let ctx = SpanContext {
+			trace_id: u128::from(u64::MAX) + 1,
+			span_id: 42,
+			parent_span_id: 7,
+			sampled: true,
+		};
+
+		let encoded = ctx.to_uber_trace_id();
+		assert_eq!(SpanContext::from_uber_trace_id(&encoded), Some(ctx));
+	}
+
+	#[test]
+	fn span_context_round_trips_unsampled() {
+		let ctx = SpanContext {
+			trace_id: 1,
+			span_id: 2,
+			parent_span_id: 0,
+			sampled: false,
+		};
+
+		let encoded = ctx.to_uber_trace_id();
+		assert_eq!(encoded, "1:2:0:0");
+		assert_eq!(SpanContext::from_uber_trace_id(&encoded), Some(ctx));
+	}
+
+	#[test]
+	fn tag_keys_are_stable_for_cross_node_filtering() {
+		// Regression: a silent rename here breaks cross-node trace filtering in the
+		// Jaeger UI, the entire point of centralizing these keys.
+		assert_eq!(tag::CANDIDATE_HASH, "candidate-hash");
+		assert_eq!(tag::POV_HASH, "pov-hash");
+		assert_eq!(tag::VALIDATOR_INDEX, "validator-index");
+		assert_eq!(tag::PARA_ID, "para-id");
+		assert_eq!(tag::RELAY_PARENT, "relay-parent");
+	}
+
+	#[test]
+	fn tag_helpers_no_op_when_disabled() {
+		let candidate_hash = CandidateHash(Hash::default());
+		let hash = Hash::default();
+		let para_id = ParaId::from(7u32);
+
+		assert!(matches!(JaegerSpan::Disabled.with_candidate(&candidate_hash), JaegerSpan::Disabled));
+		assert!(matches!(JaegerSpan::Disabled.with_pov_hash(&hash), JaegerSpan::Disabled));
+		assert!(matches!(JaegerSpan::Disabled.with_validator_index(0), JaegerSpan::Disabled));
+		assert!(matches!(JaegerSpan::Disabled.with_para_id(para_id), JaegerSpan::Disabled));
+		assert!(matches!(JaegerSpan::Disabled.with_relay_parent(&hash), JaegerSpan::Disabled));
+		assert!(matches!(
+			JaegerSpan::Disabled.child_with_candidate("child", &candidate_hash),
+			JaegerSpan::Disabled
+		));
+	}
+
+	#[test]
+	fn collector_retry_backoff_grows_exponentially() {
+		assert_eq!(collector_retry_backoff(1), Duration::from_millis(200));
+		assert_eq!(collector_retry_backoff(2), Duration::from_millis(400));
+	}
+
+	#[test]
+	fn should_retry_collector_send_stops_at_max_retries() {
+		assert!(should_retry_collector_send(0));
+		assert!(should_retry_collector_send(MAX_COLLECTOR_RETRIES - 1));
+		assert!(!should_retry_collector_send(MAX_COLLECTOR_RETRIES));
+	}
+
+	#[test]
+	fn collector_batch_channel_drops_rather_than_blocks_when_full() {
+		let (tx, _rx) = async_std::channel::bounded::<Vec<u8>>(1);
+		assert!(tx.try_send(vec![1]).is_ok());
+		// Channel at capacity: `try_send` must fail fast rather than block, so the
+		// fire-and-forget caller can drop the batch instead of stalling.
+		assert!(tx.try_send(vec![2]).is_err());
+	}
+
+	#[test]
+	fn span_context_rejects_malformed_input() {
+		assert_eq!(SpanContext::from_uber_trace_id(""), None);
+		assert_eq!(SpanContext::from_uber_trace_id("1:2:3"), None);
+		assert_eq!(SpanContext::from_uber_trace_id("1:2:3:4:5"), None);
+		assert_eq!(SpanContext::from_uber_trace_id("zz:2:3:0"), None);
 	}
 }